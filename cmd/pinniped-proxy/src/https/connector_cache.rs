@@ -0,0 +1,99 @@
+//! A process-wide cache of ready-built https clients.
+//!
+//! Building a `TlsConnector` (and exchanging the authz token with
+//! pinniped-concierge for a client identity) on every request is expensive: each
+//! miss re-runs a TokenCredentialRequest round trip and rebuilds the full TLS
+//! handshake configuration. Because the exchanged identity is stable for the life
+//! of the presented credential, we can cache the resulting `hyper::Client` keyed
+//! by the inputs that determine it and reuse it until a TTL elapses, at which
+//! point a rotated identity is re-exchanged.
+
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use dashmap::DashMap;
+use hyper::header::{HeaderMap, AUTHORIZATION};
+use hyper::client::HttpConnector;
+use hyper::Client;
+use hyper_tls::HttpsConnector;
+use once_cell::sync::Lazy;
+
+/// How long a cached client is reused before the identity is re-exchanged.
+///
+/// Kept well below the lifetime of an exchanged pinniped identity so that
+/// rotated credentials take effect without an unbounded stale window.
+///
+/// Staleness tradeoff: because the key is derived from the presented bearer
+/// token (see [`authz_fingerprint`]), a token that is revoked upstream but
+/// re-presented with the same value continues to be served from the cached
+/// mutual-TLS client until this TTL elapses. The window is deliberately short
+/// to bound that exposure. The TTL is a compile-time constant, and the native
+/// backend has no switch to bypass the cache; only the rustls backend
+/// (`PINNIPED_PROXY_TLS_BACKEND=rustls`) forwards without it.
+const CONNECTOR_TTL: Duration = Duration::from_secs(60);
+
+/// The concrete client type stored in the cache.
+pub type CachedClient = Client<HttpsConnector<HttpConnector>, hyper::Body>;
+
+struct Entry {
+    client: Arc<CachedClient>,
+    created: Instant,
+}
+
+static CACHE: Lazy<DashMap<u64, Entry>> = Lazy::new(DashMap::new);
+
+/// Fingerprints the bearer credential that will be exchanged for a client
+/// identity. Because the same authz token deterministically yields the same
+/// exchanged identity for the life of the credential, hashing it lets the cache
+/// key distinguish identities without first performing the (expensive) exchange.
+///
+/// The consequence is that a token revoked upstream but re-presented unchanged
+/// keeps serving from the cached connector until the TTL expires; see
+/// [`CONNECTOR_TTL`] for the bound on that window.
+pub fn authz_fingerprint(headers: &HeaderMap) -> Vec<u8> {
+    let mut hasher = DefaultHasher::new();
+    match headers.get(AUTHORIZATION).map(|v| v.as_bytes()) {
+        Some(token) => token.hash(&mut hasher),
+        None => b"".hash(&mut hasher),
+    }
+    hasher.finish().to_le_bytes().to_vec()
+}
+
+/// Computes the cache key for a request from the api server url, the api server
+/// CA data and the fingerprint of the credential that will be exchanged for a
+/// client identity. Distinct identities therefore never share a connector.
+pub fn cache_key(k8s_api_server_url: &str, cert_auth_data: &[u8], identity_fingerprint: &[u8]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    k8s_api_server_url.hash(&mut hasher);
+    cert_auth_data.hash(&mut hasher);
+    identity_fingerprint.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Returns the cached client for `key` if one exists and has not outlived the
+/// TTL, removing it otherwise so the caller rebuilds on a miss.
+pub fn get(key: u64) -> Option<Arc<CachedClient>> {
+    if let Some(entry) = CACHE.get(&key) {
+        if entry.created.elapsed() < CONNECTOR_TTL {
+            return Some(entry.client.clone());
+        }
+    }
+    // Expired or absent: drop any stale entry so it is rebuilt on the miss path.
+    CACHE.remove(&key);
+    None
+}
+
+/// Stores a freshly built client under `key`, returning the shared handle.
+pub fn insert(key: u64, client: CachedClient) -> Arc<CachedClient> {
+    let client = Arc::new(client);
+    CACHE.insert(
+        key,
+        Entry {
+            client: client.clone(),
+            created: Instant::now(),
+        },
+    );
+    client
+}
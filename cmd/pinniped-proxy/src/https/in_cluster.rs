@@ -0,0 +1,61 @@
+//! In-cluster kubernetes configuration.
+//!
+//! When the proxy targets the in-cluster api server (`https://kubernetes.local`)
+//! the CA bundle and api server address are available from the mounted
+//! service-account directory and the standard environment variables, so callers
+//! need not pass the CA data in request headers.
+
+use std::env;
+use std::fs;
+
+use anyhow::{Context, Result};
+
+/// The rewritten host used to denote the in-cluster api server.
+pub const IN_CLUSTER_HOST: &str = "kubernetes.local";
+
+/// Path to the CA bundle mounted into every pod for the in-cluster api server.
+const SERVICE_ACCOUNT_CA: &str = "/var/run/secrets/kubernetes.io/serviceaccount/ca.crt";
+
+/// The in-cluster api server connection details sourced from the filesystem and
+/// environment rather than request headers.
+pub struct InClusterConfig {
+    /// PEM-encoded CA bundle for the api server, as read from the mounted
+    /// service-account directory.
+    pub cert_auth_data: Vec<u8>,
+    /// The `https://host:port` url of the in-cluster api server.
+    pub api_server_url: String,
+}
+
+/// Reports whether `url` addresses the in-cluster api server, matching the host
+/// exactly so that lookalikes such as `kubernetes.local.attacker.example` are
+/// not treated as in-cluster.
+pub fn is_in_cluster_url(url: &str) -> bool {
+    // Strip the scheme, then any userinfo/path/port, and compare the host.
+    let without_scheme = url.split("://").nth(1).unwrap_or(url);
+    let authority = without_scheme.split('/').next().unwrap_or("");
+    let host = authority
+        .rsplit('@')
+        .next()
+        .unwrap_or(authority)
+        .split(':')
+        .next()
+        .unwrap_or("");
+    host == IN_CLUSTER_HOST
+}
+
+/// Loads the in-cluster CA bundle from the mounted service-account directory and
+/// the api server host/port from `KUBERNETES_SERVICE_HOST`/`KUBERNETES_SERVICE_PORT`.
+pub fn in_cluster_config() -> Result<InClusterConfig> {
+    let cert_auth_data = fs::read(SERVICE_ACCOUNT_CA)
+        .with_context(|| format!("unable to read in-cluster CA bundle from {}", SERVICE_ACCOUNT_CA))?;
+
+    let host = env::var("KUBERNETES_SERVICE_HOST")
+        .context("KUBERNETES_SERVICE_HOST is not set; are we running in-cluster?")?;
+    let port = env::var("KUBERNETES_SERVICE_PORT")
+        .context("KUBERNETES_SERVICE_PORT is not set; are we running in-cluster?")?;
+
+    Ok(InClusterConfig {
+        cert_auth_data,
+        api_server_url: format!("https://{}:{}", host, port),
+    })
+}
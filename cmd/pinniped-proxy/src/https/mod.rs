@@ -0,0 +1,161 @@
+//! HTTPS helpers for the pinniped proxy.
+//!
+//! This module parses the api server coordinates from the request headers,
+//! rewrites the incoming request to target the api server, exchanges the
+//! presented authz token with pinniped-concierge for a client identity and
+//! builds the https client used to forward the request. The submodules add the
+//! connector cache, header sanitisation, in-cluster configuration and the
+//! rustls backend.
+
+pub mod connector_cache;
+pub mod in_cluster;
+pub mod rustls_backend;
+pub mod sanitize;
+
+pub use connector_cache::authz_fingerprint;
+pub use in_cluster::{in_cluster_config, is_in_cluster_url};
+pub use rustls_backend::{apply_client_identity, make_https_client_rustls, ClientIdentity};
+pub use sanitize::{
+    add_forwarded_for, is_upgrade_request, sanitize_proxied_request, sanitize_proxied_response,
+};
+
+use anyhow::{anyhow, Context, Result};
+use hyper::client::HttpConnector;
+use hyper::header::{HeaderMap, AUTHORIZATION, CONTENT_TYPE, HOST};
+use hyper::{Body, Client, Method, Request, Uri};
+use hyper_tls::HttpsConnector;
+use native_tls::{Certificate, TlsConnector, TlsConnectorBuilder};
+
+/// Header carrying the target api server url.
+const HEADER_API_SERVER_URL: &str = "PINNIPED_PROXY_API_SERVER_URL";
+/// Header carrying the PEM-encoded api server CA bundle.
+const HEADER_API_SERVER_CERT: &str = "PINNIPED_PROXY_API_SERVER_CERT";
+/// Optional override for the concierge endpoint; defaults to the api server.
+const CONCIERGE_URL_ENV: &str = "PINNIPED_PROXY_CONCIERGE_URL";
+/// The TokenCredentialRequest endpoint served by pinniped-concierge.
+const TOKEN_CREDENTIAL_REQUEST_PATH: &str =
+    "/apis/login.concierge.pinniped.dev/v1alpha1/tokencredentialrequests";
+
+/// Reads the target api server url from the request headers.
+pub fn get_api_server_url(headers: &HeaderMap) -> Result<String> {
+    let value = headers
+        .get(HEADER_API_SERVER_URL)
+        .ok_or_else(|| anyhow!("missing required header {}", HEADER_API_SERVER_URL))?;
+    Ok(value.to_str().context("api server url header is not valid utf-8")?.to_string())
+}
+
+/// Reads the PEM-encoded api server CA bundle from the request headers.
+pub fn get_api_server_cert_auth_data(headers: &HeaderMap) -> Result<Vec<u8>> {
+    let value = headers
+        .get(HEADER_API_SERVER_CERT)
+        .ok_or_else(|| anyhow!("missing required header {}", HEADER_API_SERVER_CERT))?;
+    Ok(value.as_bytes().to_vec())
+}
+
+/// Parses PEM CA data into a native-tls [`Certificate`].
+pub fn cert_for_cert_data(cert_auth_data: Vec<u8>) -> Result<Certificate> {
+    Certificate::from_pem(&cert_auth_data).context("unable to parse api server CA data")
+}
+
+/// Rewrites the incoming request so it targets `k8s_api_server_url`, preserving
+/// the original path, query, method, headers and body.
+pub fn rewrite_request(mut req: Request<Body>, k8s_api_server_url: String) -> Result<Request<Body>> {
+    let base: Uri = k8s_api_server_url.parse().context("invalid api server url")?;
+    let authority = base
+        .authority()
+        .ok_or_else(|| anyhow!("api server url has no authority: {}", k8s_api_server_url))?
+        .clone();
+    let scheme = base.scheme().cloned().unwrap_or_else(|| "https".parse().unwrap());
+
+    let path_and_query = req
+        .uri()
+        .path_and_query()
+        .cloned()
+        .unwrap_or_else(|| "/".parse().unwrap());
+
+    let uri = Uri::builder()
+        .scheme(scheme)
+        .authority(authority.clone())
+        .path_and_query(path_and_query)
+        .build()
+        .context("unable to rewrite request uri")?;
+    *req.uri_mut() = uri;
+
+    // Point the Host header at the api server authority.
+    req.headers_mut()
+        .insert(HOST, authority.as_str().parse().context("invalid host header")?);
+    Ok(req)
+}
+
+/// Builds a hyper client over the native-tls connector described by `builder`.
+pub fn make_https_client(builder: &mut TlsConnectorBuilder) -> Result<Client<HttpsConnector<HttpConnector>>> {
+    let tls: TlsConnector = builder.build().context("unable to build TLS connector")?;
+    let mut http = HttpConnector::new();
+    http.enforce_http(false);
+    let https = HttpsConnector::from((http, tls.into()));
+    Ok(Client::builder().build(https))
+}
+
+/// Exchanges the presented authz token with pinniped-concierge for a client
+/// identity X509 cert, returning the identity to feed into whichever TLS backend
+/// is active.
+///
+/// This replaces the previous in-place mutation of a native-tls builder: the
+/// exchanged cert/key are returned as a [`ClientIdentity`] so the native and
+/// rustls backends can each consume them.
+pub async fn include_client_identity_for_headers(
+    headers: HeaderMap,
+    k8s_api_server_url: &str,
+    cert_auth_data: &[u8],
+) -> Result<ClientIdentity> {
+    let token = headers
+        .get(AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .map(|v| v.trim_start_matches("Bearer ").to_string())
+        .ok_or_else(|| anyhow!("missing authorization token for identity exchange"))?;
+
+    // The concierge is reached over TLS using the api server CA; its endpoint
+    // defaults to the api server unless explicitly overridden.
+    let concierge_base =
+        std::env::var(CONCIERGE_URL_ENV).unwrap_or_else(|_| k8s_api_server_url.to_string());
+
+    let mut builder = TlsConnector::builder();
+    builder.add_root_certificate(cert_for_cert_data(cert_auth_data.to_vec())?);
+    let client = make_https_client(&mut builder)?;
+
+    let body = format!(
+        "{{\"apiVersion\":\"login.concierge.pinniped.dev/v1alpha1\",\"kind\":\"TokenCredentialRequest\",\"spec\":{{\"token\":{:?}}}}}",
+        token,
+    );
+    let request = Request::builder()
+        .method(Method::POST)
+        .uri(format!("{}{}", concierge_base.trim_end_matches('/'), TOKEN_CREDENTIAL_REQUEST_PATH))
+        .header(CONTENT_TYPE, "application/json")
+        .header(AUTHORIZATION, format!("Bearer {}", token))
+        .body(Body::from(body))
+        .context("unable to build TokenCredentialRequest")?;
+
+    let response = client.request(request).await.context("concierge exchange failed")?;
+    let bytes = hyper::body::to_bytes(response.into_body())
+        .await
+        .context("unable to read concierge response")?;
+    let parsed: serde_json::Value =
+        serde_json::from_slice(&bytes).context("unable to parse concierge response")?;
+
+    let credential = &parsed["status"]["credential"];
+    let cert_chain_pem = credential["clientCertificateData"]
+        .as_str()
+        .ok_or_else(|| anyhow!("concierge response missing clientCertificateData"))?
+        .as_bytes()
+        .to_vec();
+    let private_key_pem = credential["clientKeyData"]
+        .as_str()
+        .ok_or_else(|| anyhow!("concierge response missing clientKeyData"))?
+        .as_bytes()
+        .to_vec();
+
+    Ok(ClientIdentity {
+        cert_chain_pem,
+        private_key_pem,
+    })
+}
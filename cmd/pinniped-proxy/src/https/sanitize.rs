@@ -0,0 +1,102 @@
+//! RFC 7230 message forwarding helpers.
+//!
+//! An intermediary must not forward connection-scoped (hop-by-hop) headers, and
+//! should record the client it forwarded on behalf of. These helpers strip the
+//! hop-by-hop set from both the forwarded request and the returned response and
+//! maintain the `X-Forwarded-For` chain.
+
+use std::net::SocketAddr;
+
+use hyper::header::{HeaderMap, HeaderValue, CONNECTION, UPGRADE};
+use hyper::{Body, Request, Response};
+
+/// The standard hop-by-hop headers that MUST NOT be forwarded by an
+/// intermediary (RFC 7230 §6.1).
+const HOP_BY_HOP_HEADERS: [&str; 8] = [
+    "connection",
+    "keep-alive",
+    "proxy-authenticate",
+    "proxy-authorization",
+    "te",
+    "trailer",
+    "transfer-encoding",
+    "upgrade",
+];
+
+/// Removes the hop-by-hop headers, including any header named in the message's
+/// own `Connection` header, from `headers`.
+fn strip_hop_by_hop(headers: &mut HeaderMap) {
+    // Collect the connection-scoped header names before mutating the map.
+    let mut connection_named: Vec<String> = Vec::new();
+    for value in headers.get_all(CONNECTION).iter() {
+        if let Ok(v) = value.to_str() {
+            for name in v.split(',') {
+                let name = name.trim();
+                if !name.is_empty() {
+                    connection_named.push(name.to_ascii_lowercase());
+                }
+            }
+        }
+    }
+
+    for name in HOP_BY_HOP_HEADERS.iter() {
+        while headers.remove(*name).is_some() {}
+    }
+    for name in connection_named {
+        while headers.remove(name.as_str()).is_some() {}
+    }
+}
+
+/// Strips hop-by-hop headers from the request and appends the peer address to
+/// the `X-Forwarded-For` chain before the request is forwarded upstream.
+pub fn sanitize_proxied_request(mut req: Request<Body>, peer_addr: SocketAddr) -> Request<Body> {
+    let headers = req.headers_mut();
+    strip_hop_by_hop(headers);
+    append_forwarded_for(headers, peer_addr);
+    req
+}
+
+/// Records the peer in `X-Forwarded-For` without stripping hop-by-hop headers.
+///
+/// Used for connection-upgrade requests (exec/attach/port-forward), where the
+/// `Connection`/`Upgrade` headers must be preserved to complete the handshake
+/// upstream.
+pub fn add_forwarded_for(mut req: Request<Body>, peer_addr: SocketAddr) -> Request<Body> {
+    append_forwarded_for(req.headers_mut(), peer_addr);
+    req
+}
+
+/// Reports whether `headers` request a connection upgrade, i.e. carry an
+/// `Upgrade` header listed in the `Connection` header.
+pub fn is_upgrade_request(headers: &HeaderMap) -> bool {
+    if !headers.contains_key(UPGRADE) {
+        return false;
+    }
+    headers.get_all(CONNECTION).iter().any(|value| {
+        value
+            .to_str()
+            .map(|v| v.split(',').any(|name| name.trim().eq_ignore_ascii_case("upgrade")))
+            .unwrap_or(false)
+    })
+}
+
+/// Strips hop-by-hop headers from the upstream response before it is returned to
+/// the client.
+pub fn sanitize_proxied_response(mut res: Response<Body>) -> Response<Body> {
+    strip_hop_by_hop(res.headers_mut());
+    res
+}
+
+/// Appends the peer address to the existing `X-Forwarded-For` value, or sets it
+/// when absent.
+fn append_forwarded_for(headers: &mut HeaderMap, peer_addr: SocketAddr) {
+    const X_FORWARDED_FOR: &str = "x-forwarded-for";
+    let peer = peer_addr.ip().to_string();
+    let value = match headers.get(X_FORWARDED_FOR).and_then(|v| v.to_str().ok()) {
+        Some(existing) => format!("{}, {}", existing, peer),
+        None => peer,
+    };
+    if let Ok(value) = HeaderValue::from_str(&value) {
+        headers.insert(X_FORWARDED_FOR, value);
+    }
+}
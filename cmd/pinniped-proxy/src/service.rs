@@ -1,4 +1,6 @@
 use std::convert::Infallible;
+use std::env;
+use std::net::SocketAddr;
 
 use anyhow::Error;
 use hyper::{Body, Request, Response, StatusCode};
@@ -7,62 +9,205 @@ use native_tls::TlsConnector;
 
 use crate::logging;
 use crate::https;
+use crate::https::connector_cache;
+
+/// Environment variable selecting the TLS backend used to proxy requests.
+///
+/// Accepts `rustls` for the pure-Rust stack or `native` (the default) for the
+/// OpenSSL-backed `native-tls` stack.
+const TLS_BACKEND_ENV: &str = "PINNIPED_PROXY_TLS_BACKEND";
+
+/// The TLS stack used when forwarding a request to the api server.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum TlsBackend {
+    Native,
+    Rustls,
+}
+
+impl TlsBackend {
+    /// Reads the backend selection from `PINNIPED_PROXY_TLS_BACKEND`, defaulting
+    /// to the native-tls stack when unset or unrecognised.
+    fn from_env() -> TlsBackend {
+        match env::var(TLS_BACKEND_ENV).as_deref() {
+            Ok("rustls") => TlsBackend::Rustls,
+            _ => TlsBackend::Native,
+        }
+    }
+}
 
 /// The proxy service accepts a request and returns the proxied response from the api server.
 ///
 /// The request must include an authorization token which is exchanged with pinniped-concierge
 /// for an X509 client identity cert with which the request is forwarded on.
-pub async fn proxy(mut req: Request<Body>) -> Result<Response<Body>, Infallible> {
+///
+/// `peer_addr` is the address of the calling client, recorded in the
+/// `X-Forwarded-For` chain of the forwarded request.
+pub async fn proxy(mut req: Request<Body>, peer_addr: SocketAddr) -> Result<Response<Body>, Infallible> {
 
     let mut log_data = logging::request_log_data(&req);
-    let k8s_api_server_url = match https::get_api_server_url(req.headers()) {
+    let mut k8s_api_server_url = match https::get_api_server_url(req.headers()) {
         Ok(u) => u,
         Err(e) => return handle_error(e, StatusCode::BAD_REQUEST, log_data),
     };
+
+    // For the in-cluster api server, source the CA bundle and the api server
+    // host/port from the mounted service-account directory and environment
+    // rather than requiring them in request headers. Resolve this before
+    // rewriting so the request is forwarded to the env-derived address.
+    let in_cluster = if https::is_in_cluster_url(&k8s_api_server_url) {
+        match https::in_cluster_config() {
+            Ok(c) => {
+                k8s_api_server_url = c.api_server_url.clone();
+                Some(c)
+            },
+            Err(e) => return handle_error(e, StatusCode::INTERNAL_SERVER_ERROR, log_data),
+        }
+    } else {
+        None
+    };
+
     req = match https::rewrite_request(req, k8s_api_server_url.clone()) {
         Ok(r) => r,
         Err(e) => return handle_error(e, StatusCode::BAD_REQUEST, log_data),
     };
 
+    // exec/attach/port-forward and log streaming rely on an HTTP connection
+    // upgrade; the Connection/Upgrade headers must survive to the api server, so
+    // such requests skip hop-by-hop stripping and only record X-Forwarded-For.
+    let is_upgrade = https::is_upgrade_request(req.headers());
+    req = if is_upgrade {
+        https::add_forwarded_for(req, peer_addr)
+    } else {
+        // Strip hop-by-hop headers and record the client in X-Forwarded-For before
+        // forwarding, as required of an RFC 7230 intermediary.
+        https::sanitize_proxied_request(req, peer_addr)
+    };
+
     // Recreate the log data now that the request host has been rewritten.
     log_data = logging::request_log_data(&req);
 
-    // TODO: don't call this if we're using https://kubernetes.local, instead
-    // grab the data from the file system.
-    let cert_auth_data = match https::get_api_server_cert_auth_data(req.headers()) {
-        Ok(c) => c,
-        Err(e) => return handle_error(e, StatusCode::BAD_REQUEST, log_data),
+    // Use the in-cluster CA bundle when targeting the in-cluster api server;
+    // only explicit external clusters still supply their CA via headers.
+    let cert_auth_data = match in_cluster {
+        Some(c) => c.cert_auth_data,
+        None => match https::get_api_server_cert_auth_data(req.headers()) {
+            Ok(c) => c,
+            Err(e) => return handle_error(e, StatusCode::BAD_REQUEST, log_data),
+        },
     };
     let k8s_api_cert = match https::cert_for_cert_data(cert_auth_data.clone()) {
         Ok(c) => c,
         Err(e) => return handle_error(e, StatusCode::BAD_REQUEST, log_data),
     };
 
-    // Create an https client with which to proxy the request.
-    // We need to construct the TlsConnector for each request so that we can set
-    // the client cert. It'd be nice if we could do the construction once and just
-    // clone to add the client cert?
-    let mut tls_builder = &mut TlsConnector::builder();
-    // Ensure we can talk to the k8s api server via TLS by setting the api server cert.
-    tls_builder = tls_builder.add_root_certificate(k8s_api_cert.clone());
-    // Ensure the user is authenticated by exchanging the header authz token for a client identity X509 cert.
-    tls_builder = match https::include_client_identity_for_headers(tls_builder, req.headers().clone(), &k8s_api_server_url, &cert_auth_data).await {
-        Ok(b) => b,
-        Err(e) => return handle_error(e, StatusCode::INTERNAL_SERVER_ERROR, log_data),
-    };
+    // Create an https client with which to proxy the request, using the TLS
+    // backend selected by PINNIPED_PROXY_TLS_BACKEND. Both backends exchange the
+    // header authz token for a client identity X509 cert via
+    // include_client_identity_for_headers so that the user is authenticated.
+    match TlsBackend::from_env() {
+        TlsBackend::Rustls => {
+            // Pure-Rust stack: useful where OpenSSL/native-tls is unavailable or
+            // produces opaque mutual-TLS errors. This connector type differs from the
+            // native one, so it is built and forwarded independently of the cache.
+            // Exchange the authz token for a client identity and feed it into the
+            // rustls connector builder.
+            let identity = match https::include_client_identity_for_headers(req.headers().clone(), &k8s_api_server_url, &cert_auth_data).await {
+                Ok(i) => i,
+                Err(e) => return handle_error(e, StatusCode::INTERNAL_SERVER_ERROR, log_data),
+            };
+            let client = match https::make_https_client_rustls(&cert_auth_data, &identity) {
+                Ok(c) => c,
+                Err(e) => return handle_error(e, StatusCode::INTERNAL_SERVER_ERROR, log_data),
+            };
+            forward(&client, req, is_upgrade, log_data).await
+        },
+        TlsBackend::Native => {
+            // Reuse a previously built client for this (api server, CA, identity)
+            // tuple rather than rebuilding the TlsConnector and re-exchanging the
+            // token with pinniped-concierge on every request.
+            let key = connector_cache::cache_key(
+                &k8s_api_server_url,
+                &cert_auth_data,
+                &https::authz_fingerprint(req.headers()),
+            );
+            let client = match connector_cache::get(key) {
+                Some(c) => c,
+                None => {
+                    // We need to construct the TlsConnector for each new identity so that we
+                    // can set the client cert. It'd be nice if we could do the construction
+                    // once and just clone to add the client cert?
+                    let tls_builder = &mut TlsConnector::builder();
+                    // Ensure we can talk to the k8s api server via TLS by setting the api server cert.
+                    tls_builder.add_root_certificate(k8s_api_cert.clone());
+                    // Ensure the user is authenticated by exchanging the header authz token for a client identity X509 cert.
+                    let identity = match https::include_client_identity_for_headers(req.headers().clone(), &k8s_api_server_url, &cert_auth_data).await {
+                        Ok(i) => i,
+                        Err(e) => return handle_error(e, StatusCode::INTERNAL_SERVER_ERROR, log_data),
+                    };
+                    if let Err(e) = https::apply_client_identity(tls_builder, &identity) {
+                        return handle_error(e, StatusCode::INTERNAL_SERVER_ERROR, log_data);
+                    }
+                    match https::make_https_client(tls_builder) {
+                        Ok(c) => connector_cache::insert(key, c),
+                        Err(e) => return handle_error(e, StatusCode::INTERNAL_SERVER_ERROR, log_data),
+                    }
+                },
+            };
+            forward(client.as_ref(), req, is_upgrade, log_data).await
+        },
+    }
+}
 
-    let client = match https::make_https_client(tls_builder) {
-        Ok(c) => c,
-        Err(e) => return handle_error(e, StatusCode::INTERNAL_SERVER_ERROR, log_data),
+/// Forwards the rewritten request through `client` and logs the response,
+/// mapping transport errors onto a 500 via [`handle_error`].
+///
+/// When `is_upgrade` is set and the api server answers with `101 Switching
+/// Protocols`, the upgraded client and server IO halves are joined with
+/// [`tokio::io::copy_bidirectional`] for the life of the connection so that
+/// interactive and streaming subcommands (exec/attach/port-forward) work.
+async fn forward<C>(client: &hyper::Client<C>, mut req: Request<Body>, is_upgrade: bool, log_data: logging::LogData) -> Result<Response<Body>, Infallible>
+where
+    C: hyper::client::connect::Connect + Clone + Send + Sync + 'static,
+{
+    // Capture the downstream (server-side) upgrade future before the request is
+    // consumed by the forward, so it can be joined with the upstream half.
+    let downstream_upgrade = if is_upgrade {
+        Some(hyper::upgrade::on(&mut req))
+    } else {
+        None
     };
 
-    match client.request(req).await {
-        Ok(r) => {
-            info!("{}", logging::response_log_data(&r, log_data));
-            Ok(r)
-        },
+    let mut res = match client.request(req).await {
+        Ok(r) => r,
         Err(e) => return handle_error(anyhow::anyhow!(e), StatusCode::INTERNAL_SERVER_ERROR, log_data),
+    };
+
+    if let Some(downstream_upgrade) = downstream_upgrade {
+        if res.status() == StatusCode::SWITCHING_PROTOCOLS {
+            // Keep this per-request client-identity TLS connection alive and shuttle
+            // bytes between the client and the api server in both directions.
+            let upstream_upgrade = hyper::upgrade::on(&mut res);
+            tokio::spawn(async move {
+                match tokio::try_join!(downstream_upgrade, upstream_upgrade) {
+                    Ok((mut downstream, mut upstream)) => {
+                        if let Err(e) = tokio::io::copy_bidirectional(&mut downstream, &mut upstream).await {
+                            error!("error proxying upgraded connection: {}", e);
+                        }
+                    },
+                    Err(e) => error!("error upgrading proxied connection: {}", e),
+                }
+            });
+            // The 101 is relayed downstream as-is; its hop-by-hop headers complete
+            // the handshake and must not be stripped.
+            info!("{}", logging::response_log_data(&res, log_data));
+            return Ok(res);
+        }
     }
+
+    // Strip hop-by-hop headers from the upstream response before returning it.
+    let res = https::sanitize_proxied_response(res);
+    info!("{}", logging::response_log_data(&res, log_data));
+    Ok(res)
 }
 
 /// handle_error converts an error into an http response.
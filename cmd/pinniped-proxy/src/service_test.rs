@@ -0,0 +1,263 @@
+//! Integration harness for the proxy path.
+//!
+//! Exercising [`crate::service::proxy`] normally needs a live TLS api server and
+//! a pinniped-concierge. This module stands up two ephemeral HTTPS servers with
+//! certs issued at runtime from a throwaway CA so the token exchange and the
+//! header-rewriting logic can be covered without external infrastructure:
+//!
+//! * a fake api server, served over mutual TLS, that echoes the subject of the
+//!   client cert it was presented back in the response body; and
+//! * a fake concierge that returns a canned client identity for a given bearer
+//!   token.
+
+#![cfg(test)]
+
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use anyhow::{anyhow, Result};
+use hyper::server::conn::Http;
+use hyper::service::service_fn;
+use hyper::{Body, Request, Response, StatusCode};
+use rcgen::{
+    BasicConstraints, Certificate, CertificateParams, DistinguishedName, DnType,
+    ExtendedKeyUsagePurpose, IsCa, KeyPair, KeyUsagePurpose,
+};
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::server::WebPkiClientVerifier;
+use rustls::{RootCertStore, ServerConfig};
+use tokio::net::TcpListener;
+use tokio::task::JoinHandle;
+use tokio_rustls::TlsAcceptor;
+
+use crate::service::proxy;
+
+/// A throwaway certificate authority used to issue server and client leaves so
+/// they validate against a real trust anchor (rather than treating a self-signed
+/// leaf as an anchor, which webpki does not guarantee to accept).
+struct TestCa {
+    cert: Certificate,
+    key: KeyPair,
+    /// PEM-encoded CA cert, used both as the api server CA data given to the
+    /// proxy and as the trust anchor for mutual-TLS client verification.
+    pem: String,
+    der: CertificateDer<'static>,
+}
+
+impl TestCa {
+    fn new() -> Result<TestCa> {
+        let mut params = CertificateParams::new(Vec::<String>::new())?;
+        let mut dn = DistinguishedName::new();
+        dn.push(DnType::CommonName, "pinniped-proxy-test-ca");
+        params.distinguished_name = dn;
+        params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        params.key_usages = vec![KeyUsagePurpose::KeyCertSign, KeyUsagePurpose::CrlSign];
+
+        let key = KeyPair::generate()?;
+        let cert = params.self_signed(&key)?;
+        Ok(TestCa {
+            pem: cert.pem(),
+            der: cert.der().clone(),
+            cert,
+            key,
+        })
+    }
+
+    /// Issues a leaf certificate signed by this CA for the given common name,
+    /// subject alt names and key-usage purpose.
+    fn issue(&self, common_name: &str, sans: &[&str], eku: ExtendedKeyUsagePurpose) -> Result<Leaf> {
+        let mut params = CertificateParams::new(sans.iter().map(|s| s.to_string()).collect::<Vec<_>>())?;
+        let mut dn = DistinguishedName::new();
+        dn.push(DnType::CommonName, common_name);
+        params.distinguished_name = dn;
+        params.extended_key_usages = vec![eku];
+
+        let key = KeyPair::generate()?;
+        let cert = params.signed_by(&key, &self.cert, &self.key)?;
+        Ok(Leaf {
+            cert_pem: cert.pem(),
+            key_pem: key.serialize_pem(),
+            cert_der: cert.der().clone(),
+            key_der: PrivateKeyDer::try_from(key.serialize_der()).map_err(|e| anyhow!(e))?,
+        })
+    }
+}
+
+/// A CA-signed leaf identity (PEM + DER cert and key).
+struct Leaf {
+    cert_pem: String,
+    key_pem: String,
+    cert_der: CertificateDer<'static>,
+    key_der: PrivateKeyDer<'static>,
+}
+
+/// A spawned test server and the address it is listening on. Dropping the handle
+/// aborts the accept loop.
+struct TestServer {
+    addr: SocketAddr,
+    task: JoinHandle<()>,
+}
+
+impl Drop for TestServer {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+/// Spawns a mutual-TLS fake api server that echoes the presented client-cert
+/// subject in the response body, letting a test assert which identity was
+/// forwarded. Client certs are verified against `client_roots`.
+async fn spawn_api_server(server: Leaf, client_roots: RootCertStore) -> Result<TestServer> {
+    let verifier = WebPkiClientVerifier::builder(Arc::new(client_roots)).build()?;
+    let config = ServerConfig::builder()
+        .with_client_cert_verifier(verifier)
+        .with_single_cert(vec![server.cert_der.clone()], server.key_der.clone_key())?;
+    let acceptor = TlsAcceptor::from(Arc::new(config));
+    let listener = TcpListener::bind(("127.0.0.1", 0)).await?;
+    let addr = listener.local_addr()?;
+
+    let task = tokio::spawn(async move {
+        loop {
+            let (tcp, _) = match listener.accept().await {
+                Ok(pair) => pair,
+                Err(_) => continue,
+            };
+            let acceptor = acceptor.clone();
+            tokio::spawn(async move {
+                let tls = match acceptor.accept(tcp).await {
+                    Ok(tls) => tls,
+                    Err(_) => return,
+                };
+                // The subject of the presented client cert is what distinguishes
+                // one exchanged identity from another; echo it so the test can
+                // assert the correct cert was presented upstream.
+                let subject = peer_subject(tls.get_ref().1.peer_certificates());
+                let svc = service_fn(move |_req: Request<Body>| {
+                    let subject = subject.clone();
+                    async move { Ok::<_, hyper::Error>(Response::new(Body::from(subject))) }
+                });
+                let _ = Http::new().serve_connection(tls, svc).await;
+            });
+        }
+    });
+
+    Ok(TestServer { addr, task })
+}
+
+/// Spawns a TLS fake concierge returning a canned client identity for any
+/// request carrying a bearer token, mirroring a TokenCredentialRequest exchange.
+async fn spawn_concierge(server: Leaf, identity: Leaf) -> Result<TestServer> {
+    let config = ServerConfig::builder()
+        .with_no_client_auth()
+        .with_single_cert(vec![server.cert_der.clone()], server.key_der.clone_key())?;
+    let acceptor = TlsAcceptor::from(Arc::new(config));
+    let listener = TcpListener::bind(("127.0.0.1", 0)).await?;
+    let addr = listener.local_addr()?;
+
+    let identity = Arc::new((identity.cert_pem, identity.key_pem));
+    let task = tokio::spawn(async move {
+        loop {
+            let (tcp, _) = match listener.accept().await {
+                Ok(pair) => pair,
+                Err(_) => continue,
+            };
+            let acceptor = acceptor.clone();
+            let identity = identity.clone();
+            tokio::spawn(async move {
+                let tls = match acceptor.accept(tcp).await {
+                    Ok(tls) => tls,
+                    Err(_) => return,
+                };
+                let svc = service_fn(move |req: Request<Body>| {
+                    let identity = identity.clone();
+                    async move {
+                        let status = if req.headers().contains_key(hyper::header::AUTHORIZATION) {
+                            StatusCode::CREATED
+                        } else {
+                            StatusCode::UNAUTHORIZED
+                        };
+                        let body = format!(
+                            "{{\"status\":{{\"credential\":{{\"clientCertificateData\":{:?},\"clientKeyData\":{:?}}}}}}}",
+                            identity.0, identity.1,
+                        );
+                        Ok::<_, hyper::Error>(Response::builder().status(status).body(Body::from(body)).unwrap())
+                    }
+                });
+                let _ = Http::new().serve_connection(tls, svc).await;
+            });
+        }
+    });
+
+    Ok(TestServer { addr, task })
+}
+
+/// Extracts the common name from the first peer certificate, or `"unknown"` when
+/// none was presented.
+fn peer_subject(peer: Option<&[CertificateDer<'static>]>) -> String {
+    let Some([cert, ..]) = peer else {
+        return "unknown".to_string();
+    };
+    match x509_parser::parse_x509_certificate(cert) {
+        Ok((_, parsed)) => parsed
+            .subject()
+            .iter_common_name()
+            .next()
+            .and_then(|cn| cn.as_str().ok())
+            .unwrap_or("unknown")
+            .to_string(),
+        Err(_) => "unknown".to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A request missing the api server url header is rejected before any
+    /// exchange is attempted, mapping to a 400 via `handle_error`.
+    #[tokio::test]
+    async fn missing_api_server_url_is_bad_request() {
+        let req = Request::builder().uri("/api").body(Body::empty()).unwrap();
+        let peer: SocketAddr = ([127, 0, 0, 1], 12345).into();
+        let res = proxy(req, peer).await.unwrap();
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+    }
+
+    /// The concierge exchanges a bearer token for the canned identity and the
+    /// api server echoes the subject of the forwarded client cert.
+    #[tokio::test]
+    async fn token_is_exchanged_and_client_cert_presented() {
+        let ca = TestCa::new().unwrap();
+        let api_leaf = ca.issue("kube-apiserver", &["127.0.0.1"], ExtendedKeyUsagePurpose::ServerAuth).unwrap();
+        let concierge_leaf = ca.issue("pinniped-concierge", &["127.0.0.1"], ExtendedKeyUsagePurpose::ServerAuth).unwrap();
+        // The identity the concierge issues; its CN is what the api server echoes.
+        let client_leaf = ca.issue("test-user", &["test-user"], ExtendedKeyUsagePurpose::ClientAuth).unwrap();
+
+        // The proxy trusts the CA for both servers; the api server verifies
+        // client certs against the same CA.
+        let mut client_roots = RootCertStore::empty();
+        client_roots.add(ca.der.clone()).unwrap();
+        let ca_pem = ca.pem.clone();
+
+        let api_server = spawn_api_server(api_leaf, client_roots).await.unwrap();
+        let concierge = spawn_concierge(concierge_leaf, client_leaf).await.unwrap();
+
+        // Point the exchange at the fake concierge.
+        std::env::set_var("PINNIPED_PROXY_CONCIERGE_URL", format!("https://{}", concierge.addr));
+
+        let req = Request::builder()
+            .uri("/api/v1/namespaces")
+            .header("PINNIPED_PROXY_API_SERVER_URL", format!("https://{}", api_server.addr))
+            .header("PINNIPED_PROXY_API_SERVER_CERT", ca_pem)
+            .header(hyper::header::AUTHORIZATION, "Bearer test-token")
+            .body(Body::empty())
+            .unwrap();
+
+        let peer: SocketAddr = ([127, 0, 0, 1], 54321).into();
+        let res = proxy(req, peer).await.unwrap();
+
+        assert_eq!(res.status(), StatusCode::OK);
+        let body = hyper::body::to_bytes(res.into_body()).await.unwrap();
+        assert_eq!(String::from_utf8_lossy(&body), "test-user");
+    }
+}